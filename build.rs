@@ -0,0 +1,5 @@
+fn main() {
+    prost_build::compile_protos(&["proto/replication.proto"], &["proto/"])
+        .expect("Unable to compile replication protobuf definitions");
+    println!("cargo:rerun-if-changed=proto/replication.proto");
+}
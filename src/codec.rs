@@ -0,0 +1,445 @@
+use tracing::trace;
+
+use crate::transport::Transport;
+
+const CRLF: &str = "\r\n";
+
+/// Compression algorithm negotiated for a connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Zstd,
+}
+
+impl Compression {
+    fn name(&self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Compression> {
+        match name {
+            "none" => Some(Compression::None),
+            "zstd" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// Encryption algorithm negotiated for a connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encryption {
+    None,
+    ChaCha20Poly1305,
+}
+
+impl Encryption {
+    fn name(&self) -> &'static str {
+        match self {
+            Encryption::None => "none",
+            Encryption::ChaCha20Poly1305 => "chacha20poly1305",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Encryption> {
+        match name {
+            "none" => Some(Encryption::None),
+            "chacha20poly1305" => Some(Encryption::ChaCha20Poly1305),
+            _ => None,
+        }
+    }
+}
+
+/// The codec pair negotiated at connection time. Every byte read from
+/// or written to the socket after the handshake is routed through
+/// [`Codec::decode`]/[`Codec::encode`], so the command dispatch core
+/// stays oblivious to the wire transformation.
+pub struct Codec {
+    compression: Compression,
+    encryption: Encryption,
+    key: Option<Vec<u8>>,
+}
+
+impl Codec {
+    /// The plaintext default used when a client does not negotiate.
+    fn plaintext() -> Codec {
+        Codec {
+            compression: Compression::None,
+            encryption: Encryption::None,
+            key: None,
+        }
+    }
+
+    /// Reads the optional handshake frame at the start of a connection
+    /// and negotiates the best mutually supported codec pair.
+    ///
+    /// The handshake is a single line of the form
+    /// `HELLO compression=none,zstd encryption=none,chacha20poly1305`.
+    /// A client that does not speak the handshake simply sends its
+    /// first command instead; that line is returned verbatim so the
+    /// connection loop can process it, keeping plaintext clients
+    /// working with the `none/none` default.
+    pub async fn negotiate<T: Transport>(
+        transport: &mut T,
+        key: Option<Vec<u8>>,
+    ) -> (Codec, Option<String>) {
+        let line = match transport.read_frame().await {
+            Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+            Err(_) => return (Codec::plaintext(), None),
+        };
+
+        let trimmed = line.trim_end_matches(['\r', '\n']);
+        if !trimmed.starts_with("HELLO") {
+            // Not a handshake, treat the line as the first command.
+            let leftover = if trimmed.is_empty() {
+                None
+            } else {
+                Some(line)
+            };
+            return (Codec::plaintext(), leftover);
+        }
+
+        let mut compression = Compression::None;
+        let mut encryption = Encryption::None;
+        for token in trimmed.split_whitespace().skip(1) {
+            if let Some(values) = token.strip_prefix("compression=") {
+                compression = best_compression(values);
+            } else if let Some(values) = token.strip_prefix("encryption=") {
+                encryption = best_encryption(values, &key);
+            }
+        }
+
+        let binary = compression != Compression::None || encryption != Encryption::None;
+
+        // The handshake response is still a plaintext line, so send it
+        // before any framing switch takes effect.
+        let response = format!(
+            "HELLO compression={} encryption={}{CRLF}",
+            compression.name(),
+            encryption.name()
+        );
+        transport.write_frame(response.as_bytes()).await;
+
+        // A non-`none` codec emits binary frames that would corrupt a
+        // line-delimited transport. Rather than disabling the codec,
+        // switch the connection to length-prefixed framing so the binary
+        // payload keeps its boundaries; message-framed transports treat
+        // this as a no-op.
+        if binary {
+            transport.enable_framed_mode();
+        }
+
+        trace!(
+            "Negotiated codec compression={} encryption={}",
+            compression.name(),
+            encryption.name()
+        );
+
+        (
+            Codec {
+                compression,
+                encryption,
+                key,
+            },
+            None,
+        )
+    }
+
+    /// Transforms inbound bytes back into the plaintext command line by
+    /// decrypting and then decompressing according to the negotiated
+    /// codecs.
+    pub fn decode(&self, bytes: Vec<u8>) -> Vec<u8> {
+        let bytes = self.decrypt(bytes);
+        self.decompress(bytes)
+    }
+
+    /// Transforms an outbound response into wire bytes by compressing
+    /// and then encrypting according to the negotiated codecs.
+    pub fn encode(&self, bytes: Vec<u8>) -> Vec<u8> {
+        let bytes = self.compress(bytes);
+        self.encrypt(bytes)
+    }
+
+    fn compress(&self, bytes: Vec<u8>) -> Vec<u8> {
+        match self.compression {
+            Compression::None => bytes,
+            Compression::Zstd => zstd::encode_all(bytes.as_slice(), 0).unwrap_or(bytes),
+        }
+    }
+
+    fn decompress(&self, bytes: Vec<u8>) -> Vec<u8> {
+        match self.compression {
+            Compression::None => bytes,
+            Compression::Zstd => zstd::decode_all(bytes.as_slice()).unwrap_or(bytes),
+        }
+    }
+
+    fn encrypt(&self, bytes: Vec<u8>) -> Vec<u8> {
+        match self.encryption {
+            Encryption::None => bytes,
+            Encryption::ChaCha20Poly1305 => crate::codec::chacha::encrypt(self.key.as_deref(), bytes),
+        }
+    }
+
+    fn decrypt(&self, bytes: Vec<u8>) -> Vec<u8> {
+        match self.encryption {
+            Encryption::None => bytes,
+            Encryption::ChaCha20Poly1305 => crate::codec::chacha::decrypt(self.key.as_deref(), bytes),
+        }
+    }
+}
+
+/// Picks the strongest compression both peers support, defaulting to
+/// `none` when there is no overlap.
+fn best_compression(values: &str) -> Compression {
+    let mut chosen = Compression::None;
+    for value in values.split(',') {
+        if let Some(Compression::Zstd) = Compression::parse(value) {
+            chosen = Compression::Zstd;
+        }
+    }
+    chosen
+}
+
+/// Picks the strongest encryption both peers support. Encryption can
+/// only be selected when the server was started with key material that
+/// is exactly 32 bytes long; a malformed key must never be negotiated,
+/// since `chacha::cipher` would then refuse it and messages would go
+/// out in the clear while the client believes encryption is on.
+fn best_encryption(values: &str, key: &Option<Vec<u8>>) -> Encryption {
+    match key {
+        Some(key) if key.len() == 32 => {}
+        _ => return Encryption::None,
+    }
+    let mut chosen = Encryption::None;
+    for value in values.split(',') {
+        if let Some(Encryption::ChaCha20Poly1305) = Encryption::parse(value) {
+            chosen = Encryption::ChaCha20Poly1305;
+        }
+    }
+    chosen
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::errors::SsacheError;
+
+    /// An in-memory [`Transport`] for exercising the handshake without a
+    /// real socket: `inbound` is drained line by line by `read_frame`,
+    /// and every `write_frame` call is appended to `outbound`.
+    struct MockTransport {
+        inbound: VecDeque<Vec<u8>>,
+        outbound: Vec<Vec<u8>>,
+        framed_mode_enabled: bool,
+    }
+
+    impl MockTransport {
+        fn new(lines: &[&str]) -> MockTransport {
+            MockTransport {
+                inbound: lines.iter().map(|line| line.as_bytes().to_vec()).collect(),
+                outbound: Vec::new(),
+                framed_mode_enabled: false,
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Transport for MockTransport {
+        async fn read_frame(&mut self) -> Result<Vec<u8>, SsacheError> {
+            self.inbound.pop_front().ok_or(SsacheError::NoDataReceived)
+        }
+
+        async fn write_frame(&mut self, bytes: &[u8]) {
+            self.outbound.push(bytes.to_vec());
+        }
+
+        async fn shutdown(&mut self) {}
+
+        fn preserves_message_boundaries(&self) -> bool {
+            false
+        }
+
+        fn enable_framed_mode(&mut self) {
+            self.framed_mode_enabled = true;
+        }
+    }
+
+    #[test]
+    fn best_compression_picks_zstd_when_offered() {
+        assert_eq!(best_compression("none,zstd"), Compression::Zstd);
+        assert_eq!(best_compression("none"), Compression::None);
+        assert_eq!(best_compression("bogus"), Compression::None);
+    }
+
+    #[test]
+    fn best_encryption_requires_a_32_byte_key() {
+        let good = Some(vec![0u8; 32]);
+        let short = Some(vec![0u8; 16]);
+
+        assert_eq!(
+            best_encryption("chacha20poly1305", &good),
+            Encryption::ChaCha20Poly1305
+        );
+        // A key of the wrong length must never be negotiated: `cipher()`
+        // would refuse it and every message would silently go out in the
+        // clear while the client believes encryption is on.
+        assert_eq!(best_encryption("chacha20poly1305", &short), Encryption::None);
+        assert_eq!(best_encryption("chacha20poly1305", &None), Encryption::None);
+    }
+
+    #[tokio::test]
+    async fn negotiate_falls_back_to_plaintext_without_a_handshake() {
+        let mut transport = MockTransport::new(&["PING\r\n"]);
+
+        let (codec, leftover) = Codec::negotiate(&mut transport, None).await;
+
+        assert_eq!(codec.compression, Compression::None);
+        assert_eq!(codec.encryption, Encryption::None);
+        assert_eq!(leftover, Some("PING\r\n".to_string()));
+        assert!(!transport.framed_mode_enabled);
+    }
+
+    #[tokio::test]
+    async fn negotiate_picks_the_best_shared_codecs_and_switches_framing() {
+        let mut transport = MockTransport::new(&["HELLO compression=none,zstd encryption=none,chacha20poly1305\r\n"]);
+
+        let (codec, leftover) = Codec::negotiate(&mut transport, Some(vec![9u8; 32])).await;
+
+        assert_eq!(codec.compression, Compression::Zstd);
+        assert_eq!(codec.encryption, Encryption::ChaCha20Poly1305);
+        assert_eq!(leftover, None);
+        assert!(transport.framed_mode_enabled);
+        assert_eq!(
+            transport.outbound[0],
+            b"HELLO compression=zstd encryption=chacha20poly1305\r\n".to_vec()
+        );
+    }
+
+    #[tokio::test]
+    async fn negotiate_refuses_encryption_with_a_malformed_key() {
+        let mut transport = MockTransport::new(&["HELLO encryption=chacha20poly1305\r\n"]);
+
+        let (codec, _) = Codec::negotiate(&mut transport, Some(vec![9u8; 16])).await;
+
+        assert_eq!(codec.encryption, Encryption::None);
+        assert_eq!(
+            transport.outbound[0],
+            b"HELLO compression=none encryption=none\r\n".to_vec()
+        );
+    }
+
+    #[tokio::test]
+    async fn encode_decode_round_trips_through_compression_and_encryption() {
+        let mut transport =
+            MockTransport::new(&["HELLO compression=zstd encryption=chacha20poly1305\r\n"]);
+        let (codec, _) = Codec::negotiate(&mut transport, Some(vec![3u8; 32])).await;
+
+        let wire = codec.encode(b"the quick brown fox".to_vec());
+        assert_ne!(wire, b"the quick brown fox");
+        assert_eq!(codec.decode(wire), b"the quick brown fox");
+    }
+}
+
+mod chacha {
+    use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+    use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+    use tracing::error;
+
+    /// ChaCha20-Poly1305 nonce width. A fresh random nonce is generated
+    /// per message and prepended to the ciphertext, so the same key never
+    /// seals two messages under the same nonce.
+    const NONCE_LEN: usize = 12;
+
+    fn cipher(key: Option<&[u8]>) -> Option<ChaCha20Poly1305> {
+        let key = key?;
+        if key.len() != 32 {
+            error!("Encryption key must be 32 bytes, encryption disabled");
+            return None;
+        }
+        Some(ChaCha20Poly1305::new(Key::from_slice(key)))
+    }
+
+    pub fn encrypt(key: Option<&[u8]>, bytes: Vec<u8>) -> Vec<u8> {
+        match cipher(key) {
+            Some(cipher) => {
+                let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+                match cipher.encrypt(&nonce, bytes.as_slice()) {
+                    // Prepend the nonce so the peer can decrypt without a
+                    // shared counter; it is not secret, only unique.
+                    Ok(ciphertext) => {
+                        let mut framed = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+                        framed.extend_from_slice(nonce.as_slice());
+                        framed.extend_from_slice(&ciphertext);
+                        framed
+                    }
+                    Err(_) => bytes,
+                }
+            }
+            None => bytes,
+        }
+    }
+
+    pub fn decrypt(key: Option<&[u8]>, bytes: Vec<u8>) -> Vec<u8> {
+        match cipher(key) {
+            Some(cipher) => {
+                if bytes.len() < NONCE_LEN {
+                    return bytes;
+                }
+                let mut nonce = [0u8; NONCE_LEN];
+                nonce.copy_from_slice(&bytes[..NONCE_LEN]);
+                match cipher.decrypt(Nonce::from_slice(&nonce), &bytes[NONCE_LEN..]) {
+                    Ok(plaintext) => plaintext,
+                    Err(_) => bytes,
+                }
+            }
+            None => bytes,
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn key(byte: u8, len: usize) -> Vec<u8> {
+            vec![byte; len]
+        }
+
+        #[test]
+        fn encrypt_decrypt_round_trips_with_a_valid_key() {
+            let k = key(7, 32);
+            let ciphertext = encrypt(Some(&k), b"hello world".to_vec());
+
+            assert_ne!(ciphertext, b"hello world");
+            assert_eq!(decrypt(Some(&k), ciphertext), b"hello world");
+        }
+
+        #[test]
+        fn encrypt_is_a_no_op_without_a_key() {
+            let bytes = encrypt(None, b"hello world".to_vec());
+            assert_eq!(bytes, b"hello world");
+        }
+
+        #[test]
+        fn encrypt_is_a_no_op_with_a_malformed_key() {
+            // `cipher()` refuses a key of the wrong length, so the bytes
+            // must pass through unchanged rather than panicking.
+            let k = key(7, 16);
+            let bytes = encrypt(Some(&k), b"hello world".to_vec());
+            assert_eq!(bytes, b"hello world");
+        }
+
+        #[test]
+        fn decrypt_with_the_wrong_key_returns_the_input_unchanged() {
+            let ciphertext = encrypt(Some(&key(1, 32)), b"hello world".to_vec());
+            let decrypted = decrypt(Some(&key(2, 32)), ciphertext.clone());
+            assert_eq!(decrypted, ciphertext);
+        }
+    }
+}
@@ -3,19 +3,42 @@ use std::time::Duration;
 use crate::errors::SsacheError;
 
 use log::debug;
+use nom::{
+    branch::alt,
+    bytes::complete::{escaped_transform, is_not},
+    character::complete::{char, multispace0, multispace1},
+    combinator::{map, opt, value},
+    multi::{many1, separated_list0},
+    sequence::delimited,
+    IResult,
+};
 
 #[derive(Debug, PartialEq)]
 pub enum Command {
     // GET key
     Get { key: String },
     // SET key value
-    Set { key: String, value: String },
+    Set {
+        key: String,
+        value: String,
+        substitute: bool,
+    },
     // EXPIRE key time(in milliseconds)
     Expire { key: String, time: Duration },
     // INCR key
     Incr { key: String },
     // DECR key
     Decr { key: String },
+    // INCRBY key amount
+    IncrBy { key: String, amount: i64 },
+    // DECRBY key amount
+    DecrBy { key: String, amount: i64 },
+    // DEL key [key ...]
+    Del { keys: Vec<String> },
+    // MGET key [key ...]
+    MGet { keys: Vec<String> },
+    // MSET key value [key value ...]
+    MSet { pairs: Vec<(String, String)> },
     // SAVE
     Save,
     // LOAD
@@ -24,12 +47,274 @@ pub enum Command {
     Quit,
     // PING message
     Ping { message: String },
+    // AUTH password
+    Auth { password: String },
+    // SUBSCRIBE channel [channel ...]
+    Subscribe { channels: Vec<String> },
+    // UNSUBSCRIBE [channel ...]
+    Unsubscribe { channels: Vec<String> },
+    // PUBLISH channel message
+    Publish { channel: String, message: String },
+    // MULTI
+    Multi,
+    // EXEC
+    Exec,
+    // DISCARD
+    Discard,
     Unknown,
 }
 const CRLF: &str = "\r\n";
 
+/// Per-connection transaction state. While `in_multi` is set every
+/// parsed command is pushed onto `queue` instead of being run, until an
+/// `EXEC` drains it or a `DISCARD` throws it away.
+#[derive(Debug, Default, PartialEq)]
+pub struct TransactionState {
+    pub queue: Vec<Command>,
+    pub in_multi: bool,
+}
+
+impl TransactionState {
+    pub fn new() -> TransactionState {
+        TransactionState::default()
+    }
+}
+
+/// Outcome of feeding a freshly parsed command through the transaction
+/// layer, telling the connection loop whether to run it now, buffer it,
+/// flush the queue or surface a protocol error.
+#[derive(Debug, PartialEq)]
+pub enum Dispatch {
+    /// Run the command immediately.
+    Run(Command),
+    /// The command was buffered inside an open `MULTI`.
+    Queued,
+    /// `MULTI` opened a transaction block.
+    Began,
+    /// `EXEC` flushed the queued commands, to be run in the order they
+    /// were queued. Execution is best-effort ordered rather than atomic:
+    /// no global lock is held across the batch, so writes from other
+    /// connections may interleave between the queued commands.
+    Execute(Vec<Command>),
+    /// `DISCARD` dropped the queued commands.
+    Discarded,
+    /// A protocol error (e.g. `EXEC` outside `MULTI`).
+    Error(String),
+}
+
+/// Decides what to do with a parsed command given the current
+/// transaction state, mutating the state as a side effect. `EXEC` or
+/// `DISCARD` issued outside of a `MULTI` block yields a distinct error.
+pub fn queue_or_dispatch(state: &mut TransactionState, command: Command) -> Dispatch {
+    match command {
+        Command::Multi => {
+            if state.in_multi {
+                Dispatch::Error(format!("-ERR MULTI calls can not be nested{CRLF}"))
+            } else {
+                state.in_multi = true;
+                Dispatch::Began
+            }
+        }
+        Command::Exec => {
+            if state.in_multi {
+                state.in_multi = false;
+                Dispatch::Execute(std::mem::take(&mut state.queue))
+            } else {
+                Dispatch::Error(format!("-ERR EXEC without MULTI{CRLF}"))
+            }
+        }
+        Command::Discard => {
+            if state.in_multi {
+                state.in_multi = false;
+                state.queue.clear();
+                Dispatch::Discarded
+            } else {
+                Dispatch::Error(format!("-ERR DISCARD without MULTI{CRLF}"))
+            }
+        }
+        other => {
+            if state.in_multi {
+                state.queue.push(other);
+                Dispatch::Queued
+            } else {
+                Dispatch::Run(other)
+            }
+        }
+    }
+}
+
+/// Parses the body of a double-quoted span, turning `\\`, `\"` and `\'`
+/// escapes into their literal character and leaving every other byte
+/// untouched. An empty `""` yields an empty string.
+fn double_quoted(input: &str) -> IResult<&str, String> {
+    delimited(
+        char('"'),
+        map(
+            opt(escaped_transform(
+                is_not("\\\""),
+                '\\',
+                alt((
+                    value("\\", char('\\')),
+                    value("\"", char('"')),
+                    value("'", char('\'')),
+                )),
+            )),
+            |body| body.unwrap_or_default(),
+        ),
+        char('"'),
+    )(input)
+}
+
+/// Single-quoted counterpart of [`double_quoted`].
+fn single_quoted(input: &str) -> IResult<&str, String> {
+    delimited(
+        char('\''),
+        map(
+            opt(escaped_transform(
+                is_not("\\'"),
+                '\\',
+                alt((
+                    value("\\", char('\\')),
+                    value("\"", char('"')),
+                    value("'", char('\'')),
+                )),
+            )),
+            |body| body.unwrap_or_default(),
+        ),
+        char('\''),
+    )(input)
+}
+
+/// A single token is a run of adjacent segments with no whitespace
+/// between them, so `a"b c"d` collapses into the one token `ab cd`,
+/// mirroring how a shell glues quoted and bare spans together.
+fn token(input: &str) -> IResult<&str, String> {
+    map(
+        many1(alt((
+            double_quoted,
+            single_quoted,
+            map(is_not(" \t\r\n\"'"), |run: &str| run.to_string()),
+        ))),
+        |segments| segments.concat(),
+    )(input)
+}
+
+fn command_line(input: &str) -> IResult<&str, Vec<String>> {
+    delimited(multispace0, separated_list0(multispace1, token), multispace0)(input)
+}
+
+/// Splits a raw command line into tokens, honoring single/double quotes,
+/// backslash escapes inside quotes and collapsing unquoted whitespace.
+/// A blank line yields an empty vector; an unterminated quote (or any
+/// other leftover input) is reported as a [`SsacheError::SyntaxError`]
+/// instead of being silently joined.
+pub fn tokenize(line: &str) -> Result<Vec<String>, SsacheError> {
+    let line = line.trim_end_matches(['\r', '\n']);
+    match command_line(line) {
+        Ok(("", tokens)) => Ok(tokens),
+        _ => {
+            debug!("unable to tokenize command line");
+            let message = format!("-ERROR unterminated or invalid quoting{CRLF}");
+            Err(SsacheError::SyntaxError { message })
+        }
+    }
+}
+
+/// A single piece of a SET value template: either a literal run of
+/// text or a `${name}` reference to another key.
+enum TemplateSegment {
+    Literal(String),
+    Reference(String),
+}
+
+/// Splits a SET value into literal and `${name}` segments, turning
+/// `$$` into a literal `$`. An unterminated `${` is a
+/// [`SsacheError::SyntaxError`]; every other `$` is kept literally.
+fn parse_template(value: &str) -> Result<Vec<TemplateSegment>, SsacheError> {
+    let mut segments = Vec::new();
+    let mut literal = String::new();
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            literal.push(c);
+            continue;
+        }
+        match chars.peek() {
+            // `$$` escapes to a single literal `$`.
+            Some('$') => {
+                chars.next();
+                literal.push('$');
+            }
+            Some('{') => {
+                chars.next();
+                if !literal.is_empty() {
+                    segments.push(TemplateSegment::Literal(std::mem::take(&mut literal)));
+                }
+                let mut name = String::new();
+                let mut closed = false;
+                for c in chars.by_ref() {
+                    if c == '}' {
+                        closed = true;
+                        break;
+                    }
+                    name.push(c);
+                }
+                if !closed {
+                    debug!("unterminated substitution in SET value");
+                    let message = format!("-ERROR unterminated ${{ in value{CRLF}");
+                    return Err(SsacheError::SyntaxError { message });
+                }
+                segments.push(TemplateSegment::Reference(name));
+            }
+            // A lone `$` is kept as-is.
+            _ => literal.push('$'),
+        }
+    }
+    if !literal.is_empty() {
+        segments.push(TemplateSegment::Literal(literal));
+    }
+    Ok(segments)
+}
+
+/// Returns the key names referenced by `${name}` placeholders in a SET
+/// value, in order, so the execution layer knows what to look up before
+/// interpolating. Also validates the template's quoting.
+pub fn referenced_keys(value: &str) -> Result<Vec<String>, SsacheError> {
+    Ok(parse_template(value)?
+        .into_iter()
+        .filter_map(|segment| match segment {
+            TemplateSegment::Reference(name) => Some(name),
+            TemplateSegment::Literal(_) => None,
+        })
+        .collect())
+}
+
+/// Interpolates a SET value against previously resolved key lookups,
+/// substituting a missing reference with the empty string, matching
+/// shell semantics. The template is assumed valid (already checked at
+/// parse time).
+pub fn expand_value(value: &str, resolved: &std::collections::HashMap<String, String>) -> String {
+    parse_template(value)
+        .map(|segments| {
+            segments
+                .iter()
+                .map(|segment| match segment {
+                    TemplateSegment::Literal(literal) => literal.clone(),
+                    TemplateSegment::Reference(name) => {
+                        resolved.get(name).cloned().unwrap_or_default()
+                    }
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
 pub fn parse_command(command_line: Vec<String>) -> Result<Command, SsacheError> {
-    let command = command_line.get(0).unwrap();
+    let command = match command_line.first() {
+        Some(command) => command,
+        // An empty token list carries no command to dispatch.
+        None => return Ok(Command::Unknown),
+    };
     if command.eq(&String::from("GET")) {
         if let Some(key) = command_line.get(1) {
             Ok(Command::Get {
@@ -42,9 +327,18 @@ pub fn parse_command(command_line: Vec<String>) -> Result<Command, SsacheError>
         }
     } else if command.eq(&String::from("SET")) {
         if let (Some(key), Some(_)) = (command_line.get(1), command_line.get(2)) {
+            let value = command_line[2..].join(" ");
+            // A value carrying a `$` may reference other keys; validate
+            // the template up front so an unclosed `${` is a clean parse
+            // error instead of surfacing at write time.
+            let substitute = value.contains('$');
+            if substitute {
+                referenced_keys(&value)?;
+            }
             Ok(Command::Set {
                 key: key.to_string(),
-                value: command_line[2..].join(" "),
+                value,
+                substitute,
             })
         } else {
             debug!("not enough parameters for SET command");
@@ -87,18 +381,145 @@ pub fn parse_command(command_line: Vec<String>) -> Result<Command, SsacheError>
             let message = format!("-ERROR not enough parameters for DECR{CRLF}");
             Err(SsacheError::NotEnoughParameters { message })
         }
+    } else if command.eq(&String::from("INCRBY")) {
+        if let (Some(key), Some(amount)) = (command_line.get(1), command_line.get(2)) {
+            match amount.parse::<i64>() {
+                Ok(amount) => Ok(Command::IncrBy {
+                    key: key.to_string(),
+                    amount,
+                }),
+                Err(_) => {
+                    debug!("invalid amount for INCRBY command");
+                    let message = format!("-ERROR the amount is not a valid number{CRLF}");
+                    Err(SsacheError::NotEnoughParameters { message })
+                }
+            }
+        } else {
+            debug!("not enough parameters for INCRBY command");
+            let message = format!("-ERROR not enough parameters for INCRBY{CRLF}");
+            Err(SsacheError::NotEnoughParameters { message })
+        }
+    } else if command.eq(&String::from("DECRBY")) {
+        if let (Some(key), Some(amount)) = (command_line.get(1), command_line.get(2)) {
+            match amount.parse::<i64>() {
+                Ok(amount) => Ok(Command::DecrBy {
+                    key: key.to_string(),
+                    amount,
+                }),
+                Err(_) => {
+                    debug!("invalid amount for DECRBY command");
+                    let message = format!("-ERROR the amount is not a valid number{CRLF}");
+                    Err(SsacheError::NotEnoughParameters { message })
+                }
+            }
+        } else {
+            debug!("not enough parameters for DECRBY command");
+            let message = format!("-ERROR not enough parameters for DECRBY{CRLF}");
+            Err(SsacheError::NotEnoughParameters { message })
+        }
+    } else if command.eq(&String::from("DEL")) {
+        if command_line.len() < 2 {
+            debug!("not enough parameters for DEL command");
+            let message = format!("-ERROR not enough parameters for DEL{CRLF}");
+            Err(SsacheError::NotEnoughParameters { message })
+        } else {
+            Ok(Command::Del {
+                keys: command_line[1..].to_vec(),
+            })
+        }
+    } else if command.eq(&String::from("MGET")) {
+        if command_line.len() < 2 {
+            debug!("not enough parameters for MGET command");
+            let message = format!("-ERROR not enough parameters for MGET{CRLF}");
+            Err(SsacheError::NotEnoughParameters { message })
+        } else {
+            Ok(Command::MGet {
+                keys: command_line[1..].to_vec(),
+            })
+        }
+    } else if command.eq(&String::from("MSET")) {
+        let arguments = &command_line[1..];
+        // MSET needs at least one key/value pair and an even number of
+        // arguments so every key is matched with a value.
+        if arguments.is_empty() || arguments.len() % 2 != 0 {
+            debug!("invalid number of parameters for MSET command");
+            let message = format!("-ERROR wrong number of parameters for MSET{CRLF}");
+            Err(SsacheError::NotEnoughParameters { message })
+        } else {
+            let pairs = arguments
+                .chunks(2)
+                .map(|pair| (pair[0].to_string(), pair[1].to_string()))
+                .collect();
+            Ok(Command::MSet { pairs })
+        }
     } else if command.eq(&String::from("LOAD")) {
         Ok(Command::Load)
+    } else if command.eq(&String::from("MULTI")) {
+        Ok(Command::Multi)
+    } else if command.eq(&String::from("EXEC")) {
+        Ok(Command::Exec)
+    } else if command.eq(&String::from("DISCARD")) {
+        Ok(Command::Discard)
     } else if command.eq(&String::from("QUIT")) {
         Ok(Command::Quit)
     } else if command.eq(&String::from("PING")) {
         let value = command_line[1..].join(" ");
         Ok(Command::Ping { message: value })
+    } else if command.eq(&String::from("AUTH")) {
+        // An absent password is treated as an empty one so the
+        // execution layer can reject it with the invalid password
+        // error.
+        let password = command_line.get(1).cloned().unwrap_or_default();
+        Ok(Command::Auth { password })
+    } else if command.eq(&String::from("SUBSCRIBE")) {
+        if command_line.len() < 2 {
+            debug!("not enough parameters for SUBSCRIBE command");
+            let message = format!("-ERROR not enough parameters for SUBSCRIBE{CRLF}");
+            Err(SsacheError::NotEnoughParameters { message })
+        } else {
+            Ok(Command::Subscribe {
+                channels: command_line[1..].to_vec(),
+            })
+        }
+    } else if command.eq(&String::from("UNSUBSCRIBE")) {
+        Ok(Command::Unsubscribe {
+            channels: command_line[1..].to_vec(),
+        })
+    } else if command.eq(&String::from("PUBLISH")) {
+        if let (Some(channel), Some(_)) = (command_line.get(1), command_line.get(2)) {
+            Ok(Command::Publish {
+                channel: channel.to_string(),
+                message: command_line[2..].join(" "),
+            })
+        } else {
+            debug!("not enough parameters for PUBLISH command");
+            let message = format!("-ERROR not enough parameters for PUBLISH{CRLF}");
+            Err(SsacheError::NotEnoughParameters { message })
+        }
     } else {
         Ok(Command::Unknown)
     }
 }
 
+/// Parses a request buffer that may carry several commands back to
+/// back, splitting on `\r\n` boundaries and parsing each non-empty line
+/// in order. The returned vector preserves request order so responses
+/// line up with requests. A parse error on any single line short
+/// circuits with that line's error instead of returning the commands
+/// that preceded it.
+pub fn parse_pipeline(buffer: &str) -> Result<Vec<Command>, SsacheError> {
+    let mut commands = Vec::new();
+    for line in buffer.split(CRLF) {
+        let tokens = tokenize(line)?;
+        // Blank lines between commands carry nothing to dispatch.
+        if tokens.is_empty() {
+            continue;
+        }
+        commands.push(parse_command(tokens)?);
+    }
+    Ok(commands)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -143,6 +564,93 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_auth_command_with_password() {
+        let command_line = vec!["AUTH".to_string(), "secret".to_string()];
+
+        let result = parse_command(command_line);
+
+        assert!(result.is_ok());
+        assert_eq!(
+            result.unwrap(),
+            Command::Auth {
+                password: "secret".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn parse_auth_command_without_password() {
+        let command_line = vec!["AUTH".to_string()];
+
+        let result = parse_command(command_line);
+
+        assert!(result.is_ok());
+        assert_eq!(
+            result.unwrap(),
+            Command::Auth {
+                password: "".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn parse_subscribe_command_with_multiple_channels() {
+        let command_line = vec![
+            "SUBSCRIBE".to_string(),
+            "news".to_string(),
+            "sports".to_string(),
+        ];
+
+        let result = parse_command(command_line);
+
+        assert!(result.is_ok());
+        assert_eq!(
+            result.unwrap(),
+            Command::Subscribe {
+                channels: vec!["news".to_string(), "sports".to_string()]
+            }
+        );
+    }
+
+    #[test]
+    fn parse_subscribe_command_without_channels() {
+        let command_line = vec!["SUBSCRIBE".to_string()];
+
+        let result = parse_command(command_line);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn parse_publish_command_with_enough_arguments() {
+        let command_line = vec![
+            "PUBLISH".to_string(),
+            "news".to_string(),
+            "hello world".to_string(),
+        ];
+
+        let result = parse_command(command_line);
+
+        assert!(result.is_ok());
+        assert_eq!(
+            result.unwrap(),
+            Command::Publish {
+                channel: "news".to_string(),
+                message: "hello world".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_publish_command_without_message() {
+        let command_line = vec!["PUBLISH".to_string(), "news".to_string()];
+
+        let result = parse_command(command_line);
+
+        assert!(result.is_err());
+    }
+
     #[test]
     fn parse_quit_command() {
         let command_line = vec!["QUIT".to_string()];
@@ -227,6 +735,7 @@ mod tests {
             Command::Set {
                 key: "key".to_string(),
                 value: String::from("value"),
+                substitute: false,
             }
         );
     }
@@ -247,6 +756,7 @@ mod tests {
             Command::Set {
                 key: "key".to_string(),
                 value: "value with spaces".to_string(),
+                substitute: false,
             }
         );
     }
@@ -332,4 +842,335 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn tokenize_collapses_unquoted_whitespace() {
+        let result = tokenize("SET   key    value");
+
+        assert_eq!(
+            result.unwrap(),
+            vec![
+                "SET".to_string(),
+                "key".to_string(),
+                "value".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenize_keeps_spaces_inside_double_quotes() {
+        let result = tokenize("SET key \"value with spaces\"");
+
+        assert_eq!(
+            result.unwrap(),
+            vec![
+                "SET".to_string(),
+                "key".to_string(),
+                "value with spaces".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenize_keeps_spaces_inside_single_quotes() {
+        let result = tokenize("SET key 'value with spaces'");
+
+        assert_eq!(
+            result.unwrap(),
+            vec![
+                "SET".to_string(),
+                "key".to_string(),
+                "value with spaces".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenize_preserves_escaped_quote() {
+        let result = tokenize("SET key \"a \\\" b\"");
+
+        assert_eq!(
+            result.unwrap(),
+            vec![
+                "SET".to_string(),
+                "key".to_string(),
+                "a \" b".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenize_empty_line_is_empty() {
+        let result = tokenize("\r\n");
+
+        assert_eq!(result.unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn tokenize_unterminated_quote_is_an_error() {
+        let result = tokenize("SET key \"unterminated");
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn parse_empty_command_line_is_unknown() {
+        let result = parse_command(vec![]);
+
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap(), Command::Unknown);
+    }
+
+    #[test]
+    fn parse_pipeline_preserves_order() {
+        let result = parse_pipeline("SET key value\r\nGET key\r\nPING");
+
+        assert_eq!(
+            result.unwrap(),
+            vec![
+                Command::Set {
+                    key: "key".to_string(),
+                    value: "value".to_string(),
+                    substitute: false,
+                },
+                Command::Get {
+                    key: "key".to_string(),
+                },
+                Command::Ping {
+                    message: "".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_pipeline_skips_blank_lines() {
+        let result = parse_pipeline("PING\r\n\r\nPING");
+
+        assert_eq!(result.unwrap().len(), 2);
+    }
+
+    #[test]
+    fn parse_pipeline_short_circuits_on_error() {
+        let result = parse_pipeline("PING\r\nGET\r\nPING");
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn exec_outside_multi_is_an_error() {
+        let mut state = TransactionState::new();
+
+        let dispatch = queue_or_dispatch(&mut state, Command::Exec);
+
+        assert!(matches!(dispatch, Dispatch::Error(_)));
+    }
+
+    #[test]
+    fn discard_outside_multi_is_an_error() {
+        let mut state = TransactionState::new();
+
+        let dispatch = queue_or_dispatch(&mut state, Command::Discard);
+
+        assert!(matches!(dispatch, Dispatch::Error(_)));
+    }
+
+    #[test]
+    fn multi_queues_commands_until_exec() {
+        let mut state = TransactionState::new();
+
+        assert_eq!(queue_or_dispatch(&mut state, Command::Multi), Dispatch::Began);
+        assert_eq!(
+            queue_or_dispatch(
+                &mut state,
+                Command::Set {
+                    key: "key".to_string(),
+                    value: "value".to_string(),
+                    substitute: false,
+                }
+            ),
+            Dispatch::Queued
+        );
+        let dispatch = queue_or_dispatch(&mut state, Command::Exec);
+
+        assert_eq!(
+            dispatch,
+            Dispatch::Execute(vec![Command::Set {
+                key: "key".to_string(),
+                value: "value".to_string(),
+                substitute: false,
+            }])
+        );
+        assert!(!state.in_multi);
+        assert!(state.queue.is_empty());
+    }
+
+    #[test]
+    fn parse_incrby_command_with_enough_arguments() {
+        let command_line = vec!["INCRBY".to_string(), "key".to_string(), "5".to_string()];
+
+        let result = parse_command(command_line);
+
+        assert_eq!(
+            result.unwrap(),
+            Command::IncrBy {
+                key: "key".to_string(),
+                amount: 5,
+            }
+        );
+    }
+
+    #[test]
+    fn parse_incrby_command_with_invalid_amount() {
+        let command_line = vec!["INCRBY".to_string(), "key".to_string(), "abc".to_string()];
+
+        let result = parse_command(command_line);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn parse_decrby_command_with_enough_arguments() {
+        let command_line = vec!["DECRBY".to_string(), "key".to_string(), "3".to_string()];
+
+        let result = parse_command(command_line);
+
+        assert_eq!(
+            result.unwrap(),
+            Command::DecrBy {
+                key: "key".to_string(),
+                amount: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn parse_del_command_requires_a_key() {
+        let result = parse_command(vec!["DEL".to_string()]);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn parse_del_command_with_multiple_keys() {
+        let command_line = vec!["DEL".to_string(), "a".to_string(), "b".to_string()];
+
+        let result = parse_command(command_line);
+
+        assert_eq!(
+            result.unwrap(),
+            Command::Del {
+                keys: vec!["a".to_string(), "b".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn parse_mget_command_with_multiple_keys() {
+        let command_line = vec!["MGET".to_string(), "a".to_string(), "b".to_string()];
+
+        let result = parse_command(command_line);
+
+        assert_eq!(
+            result.unwrap(),
+            Command::MGet {
+                keys: vec!["a".to_string(), "b".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn parse_mset_command_with_pairs() {
+        let command_line = vec![
+            "MSET".to_string(),
+            "a".to_string(),
+            "1".to_string(),
+            "b".to_string(),
+            "2".to_string(),
+        ];
+
+        let result = parse_command(command_line);
+
+        assert_eq!(
+            result.unwrap(),
+            Command::MSet {
+                pairs: vec![
+                    ("a".to_string(), "1".to_string()),
+                    ("b".to_string(), "2".to_string()),
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn parse_mset_command_with_odd_arguments_is_an_error() {
+        let command_line = vec!["MSET".to_string(), "a".to_string(), "1".to_string(), "b".to_string()];
+
+        let result = parse_command(command_line);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn parse_set_with_reference_flags_substitution() {
+        let command_line = vec![
+            "SET".to_string(),
+            "a".to_string(),
+            "${b}".to_string(),
+        ];
+
+        let result = parse_command(command_line);
+
+        assert_eq!(
+            result.unwrap(),
+            Command::Set {
+                key: "a".to_string(),
+                value: "${b}".to_string(),
+                substitute: true,
+            }
+        );
+    }
+
+    #[test]
+    fn parse_set_with_unclosed_reference_is_an_error() {
+        let command_line = vec![
+            "SET".to_string(),
+            "a".to_string(),
+            "${b".to_string(),
+        ];
+
+        let result = parse_command(command_line);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn referenced_keys_lists_every_placeholder() {
+        let result = referenced_keys("${a} and ${b}");
+
+        assert_eq!(result.unwrap(), vec!["a".to_string(), "b".to_string()]);
+    }
+
+    #[test]
+    fn expand_value_substitutes_and_escapes() {
+        let mut resolved = std::collections::HashMap::new();
+        resolved.insert("b".to_string(), "world".to_string());
+
+        let result = expand_value("$$hello ${b} ${missing}", &resolved);
+
+        assert_eq!(result, "$hello world ");
+    }
+
+    #[test]
+    fn discard_drops_the_queue() {
+        let mut state = TransactionState::new();
+
+        queue_or_dispatch(&mut state, Command::Multi);
+        queue_or_dispatch(&mut state, Command::Get { key: "key".to_string() });
+        let dispatch = queue_or_dispatch(&mut state, Command::Discard);
+
+        assert_eq!(dispatch, Dispatch::Discarded);
+        assert!(!state.in_multi);
+        assert!(state.queue.is_empty());
+    }
 }
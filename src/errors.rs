@@ -5,6 +5,7 @@ use std::fmt;
 pub enum SsacheError {
     NoDataReceived,
     NotEnoughParameters { message: String },
+    SyntaxError { message: String },
 }
 
 impl fmt::Display for SsacheError {
@@ -16,6 +17,9 @@ impl fmt::Display for SsacheError {
             Self::NotEnoughParameters { message: _ } => {
                 write!(f, "Not enough parameters on command")
             }
+            Self::SyntaxError { message: _ } => {
+                write!(f, "Unable to parse command line")
+            }
         }
     }
 }
@@ -1,15 +1,27 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
 
 use clap::Parser;
 use clokwerk::{AsyncScheduler, TimeUnits};
+use prost::Message;
+use pubsub::PubSub;
 use storage::ShardedStorage;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::sync::Semaphore;
 use tracing::{debug, info, trace, warn};
+use transport::{TcpTransport, Transport, WebSocketTransport};
 
+mod codec;
 mod command;
 mod errors;
+mod pubsub;
 mod storage;
+mod transport;
 
 #[derive(Parser, Debug)]
 struct Args {
@@ -21,6 +33,20 @@ struct Args {
     #[arg(short, long, default_value_t = 8)]
     shards: usize,
 
+    /// Maximum number of entries kept per shard before the W-TinyLFU
+    /// admission policy starts evicting
+    #[arg(long, default_value_t = 100_000)]
+    max_per_shard: usize,
+
+    /// Number of counters in each shard's Bloom filter used to reject
+    /// lookups for keys that were never set
+    #[arg(long, default_value_t = 1 << 20)]
+    bloom_bits: usize,
+
+    /// Number of hash probes per key in each shard's Bloom filter
+    #[arg(long, default_value_t = 4)]
+    bloom_hashes: usize,
+
     /// Enable the scheduled background job to save the data to disk
     #[arg(short, long, default_value_t = false)]
     enable_scheduled_save: bool,
@@ -40,6 +66,52 @@ struct Args {
     /// The replication interval in minutes
     #[arg(long, default_value_t = 10)]
     replication_interval: u32,
+
+    /// Port for the replica-side replication listener. When set, this
+    /// node accepts protobuf `ReplicationBatch` frames from a primary,
+    /// applies them and replies with an `Ack`. A primary points its
+    /// `--replicas` at this port.
+    #[arg(long)]
+    replication_port: Option<u16>,
+
+    /// Require clients to authenticate with AUTH before issuing any
+    /// other command, using the given secret
+    #[arg(long)]
+    requirepass: Option<String>,
+
+    /// 32-byte key (as a raw string) used when a connection negotiates
+    /// the chacha20poly1305 encryption codec. A connection that
+    /// negotiates a binary codec switches to length-prefixed framing, so
+    /// encryption is carried over both the raw TCP and WebSocket
+    /// listeners.
+    #[arg(long)]
+    encryption_key: Option<String>,
+
+    /// Maximum number of connections handled concurrently
+    #[arg(long, default_value_t = 1024)]
+    max_connections: usize,
+
+    /// Port for the optional WebSocket listener. When set, a second
+    /// listener accepts WebSocket upgrades and tunnels the line
+    /// protocol so browser and proxy clients can reach ssache without
+    /// a raw TCP socket.
+    #[arg(long)]
+    ws_port: Option<u16>,
+
+    /// Directory holding the durable write-ahead log. When set, every
+    /// mutation is appended and fsynced before the reply and the storage
+    /// is recovered from the log and latest snapshot on boot.
+    #[arg(long)]
+    wal_dir: Option<String>,
+
+    /// Directory for the disk-backed blob storage engine. When set,
+    /// values are kept in rotating segment files instead of in RAM.
+    #[arg(long)]
+    blob_dir: Option<String>,
+
+    /// Size in bytes of each blob segment file before it rolls over
+    #[arg(long, default_value_t = 64 * 1024 * 1024)]
+    blob_segment_size: u64,
 }
 
 #[tokio::main]
@@ -47,7 +119,28 @@ async fn main() {
     tracing_subscriber::fmt::init();
     let args = Args::parse();
 
-    let storage = Arc::new(ShardedStorage::new(args.shards, args.replicas.clone()));
+    let mut storage = ShardedStorage::new(
+        args.shards,
+        args.max_per_shard,
+        args.bloom_bits,
+        args.bloom_hashes,
+        args.replicas.clone(),
+    );
+    if let Some(blob_dir) = &args.blob_dir {
+        storage = storage
+            .with_blob_backend(blob_dir.into(), args.blob_segment_size)
+            .expect("Unable to open the blob storage directory");
+    }
+    if let Some(wal_dir) = &args.wal_dir {
+        storage = storage
+            .with_wal(wal_dir.into())
+            .expect("Unable to open the write-ahead log directory");
+    }
+    let storage = Arc::new(storage);
+    if args.wal_dir.is_some() {
+        storage.recover().await;
+    }
+    let pubsub = Arc::new(PubSub::new());
 
     if args.enable_scheduled_save {
         enable_scheduled_save_job(storage.clone(), &args);
@@ -57,10 +150,22 @@ async fn main() {
         enable_replication(storage.clone(), &args);
     }
 
+    if let Some(replication_port) = args.replication_port {
+        enable_replication_listener(storage.clone(), replication_port);
+    }
+
     enable_expiration_job(storage.clone());
 
     let listener = start_server(&args).await;
-    handle_connections(listener, storage).await;
+    let encryption_key = args.encryption_key.clone().map(|k| k.into_bytes());
+    if let Some(key) = &encryption_key {
+        assert!(
+            key.len() == 32,
+            "--encryption-key must be exactly 32 bytes, got {}",
+            key.len()
+        );
+    }
+    handle_connections(listener, storage, pubsub, &args, encryption_key).await;
 }
 
 fn enable_expiration_job(storage: Arc<ShardedStorage>) {
@@ -70,7 +175,6 @@ fn enable_expiration_job(storage: Arc<ShardedStorage>) {
         async move {
             trace!("Checking for expired keys");
             storage.check_expirations().await;
-            storage.remove_expiration().await;
         }
     });
 
@@ -108,26 +212,132 @@ fn enable_scheduled_save_job(storage: Arc<ShardedStorage>, args: &Args) {
     });
 }
 
+/// Starts one long-lived task per replica. Each task periodically
+/// drains the pending operation log and ships it as a prost batch,
+/// retrying with exponential backoff on failure so a replica restart
+/// or a transient outage never drops buffered writes.
 fn enable_replication(storage: Arc<ShardedStorage>, args: &Args) {
-    let mut scheduler = AsyncScheduler::new();
-    scheduler
-        .every(args.replication_interval.minutes())
-        .run(move || {
-            let storage = storage.clone();
-            async move {
-                debug!("Running replication process");
-                storage.broadcast_to_replicas().await;
-            }
+    let interval = Duration::from_secs(u64::from(args.replication_interval) * 60);
+    for replica in args.replicas.clone() {
+        let storage = storage.clone();
+        tokio::spawn(async move {
+            replicate_to(storage, replica, interval).await;
         });
+    }
+}
+
+/// Upper bound on how long graceful shutdown waits for in-flight
+/// connections to drain before exiting regardless, so a parked idle or
+/// subscribed client cannot block shutdown forever.
+const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+const REPLICATION_BACKOFF_START: Duration = Duration::from_millis(500);
+const REPLICATION_BACKOFF_CAP: Duration = Duration::from_secs(30);
 
+async fn replicate_to(storage: Arc<ShardedStorage>, replica: String, interval: Duration) {
+    // Entries stay owned by the task until the replica acknowledges
+    // them, so a failed send re-queues them for the next attempt
+    // instead of losing the batch.
+    let mut pending = storage::replication::ReplicationBatch::default();
+    let mut backoff = REPLICATION_BACKOFF_START;
+    loop {
+        let mut batch = storage.drain_replication_batch(&replica).await;
+        pending.entries.append(&mut batch.entries);
+
+        if pending.entries.is_empty() {
+            tokio::time::sleep(interval).await;
+            continue;
+        }
+
+        match storage.replicate_batch(&replica, &pending).await {
+            Ok(ack) if !ack.missing_digests.is_empty() => {
+                // The replica was missing chunks we optimistically
+                // believed it held — it most likely restarted and lost its
+                // cache. Re-attach those chunk bodies to the pending batch
+                // and retry it without clearing, so no write is lost.
+                warn!(
+                    "Replica {replica} missing {} chunk(s), resending",
+                    ack.missing_digests.len()
+                );
+                storage
+                    .rehydrate_missing_chunks(&replica, &mut pending, &ack.missing_digests)
+                    .await;
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(REPLICATION_BACKOFF_CAP);
+            }
+            Ok(_) => {
+                debug!("Replicated {} entries to {replica}", pending.entries.len());
+                pending.entries.clear();
+                backoff = REPLICATION_BACKOFF_START;
+                tokio::time::sleep(interval).await;
+            }
+            Err(e) => {
+                warn!("Error replicating to {replica}, retrying in {backoff:?}: {e}");
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(REPLICATION_BACKOFF_CAP);
+            }
+        }
+    }
+}
+
+/// Starts the replica-side listener that receives replication batches
+/// from a primary. Each accepted connection carries a single
+/// length-delimited `ReplicationBatch`, which is applied to local
+/// storage before an `Ack` is written back.
+fn enable_replication_listener(storage: Arc<ShardedStorage>, port: u16) {
     tokio::spawn(async move {
+        let listener = match TcpListener::bind(format!("127.0.0.1:{port}")).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                warn!("Unable to start replication listener on port {port}: {e}");
+                return;
+            }
+        };
+        info!("Ssache is ready to accept replication on port {port}");
         loop {
-            scheduler.run_pending().await;
-            tokio::time::sleep(Duration::from_millis(100)).await;
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let storage = storage.clone();
+                    tokio::spawn(async move {
+                        handle_replication_connection(storage, stream).await;
+                    });
+                }
+                Err(e) => warn!("Error accepting replication connection, {e}"),
+            }
         }
     });
 }
 
+/// Reads a length-delimited `ReplicationBatch` off `stream`, applies it
+/// and replies with a length-delimited `Ack`. The primary half-closes
+/// its write side after sending, so `read_to_end` returns once the whole
+/// frame is buffered.
+async fn handle_replication_connection(storage: Arc<ShardedStorage>, mut stream: TcpStream) {
+    let mut buf = Vec::new();
+    if let Err(e) = stream.read_to_end(&mut buf).await {
+        warn!("Error reading replication batch {e}");
+        return;
+    }
+    let batch = match storage::replication::ReplicationBatch::decode_length_delimited(buf.as_slice())
+    {
+        Ok(batch) => batch,
+        Err(e) => {
+            warn!("Error decoding replication batch {e}");
+            return;
+        }
+    };
+    let ack = storage.apply_replication_batch(&batch).await;
+    let mut response = Vec::with_capacity(ack.encoded_len() + 10);
+    if let Err(e) = ack.encode_length_delimited(&mut response) {
+        warn!("Error encoding replication ack {e}");
+        return;
+    }
+    if let Err(e) = stream.write_all(&response).await {
+        warn!("Error sending replication ack {e}");
+    }
+    let _ = stream.shutdown().await;
+}
+
 async fn start_server(args: &Args) -> TcpListener {
     info!("Ssache is starting");
 
@@ -142,60 +352,421 @@ async fn start_server(args: &Args) -> TcpListener {
     listener
 }
 
-async fn handle_connections(listener: TcpListener, storage: Arc<ShardedStorage>) {
+async fn start_ws_server(ws_port: u16) -> TcpListener {
+    let listener = match TcpListener::bind(format!("127.0.0.1:{ws_port}")).await {
+        Ok(listener) => listener,
+        Err(e) => panic!("Unable to start ssache WebSocket listener on port {ws_port}. Error = {:?}", e),
+    };
+
+    info!("Ssache is ready to accept WebSocket connections on port {ws_port}");
+
+    listener
+}
+
+/// Acquires a connection permit, returning [`None`] only when the
+/// semaphore has been closed during shutdown.
+async fn acquire_permit(
+    semaphore: &Arc<Semaphore>,
+) -> Option<tokio::sync::OwnedSemaphorePermit> {
+    semaphore.clone().acquire_owned().await.ok()
+}
+
+/// Awaits the next WebSocket connection, or blocks forever when no
+/// WebSocket listener is configured so the `select!` arm stays inert.
+async fn accept_ws(listener: Option<&TcpListener>) -> Result<TcpStream, std::io::Error> {
+    match listener {
+        Some(listener) => listener.accept().await.map(|(stream, _)| stream),
+        None => std::future::pending().await,
+    }
+}
+
+async fn handle_connections(
+    listener: TcpListener,
+    storage: Arc<ShardedStorage>,
+    pubsub: Arc<PubSub>,
+    args: &Args,
+    encryption_key: Option<Vec<u8>>,
+) {
+    let requirepass = args.requirepass.clone();
+    // Bounds the number of connections handled at once so a flood of
+    // clients cannot exhaust resources; a permit is held for the whole
+    // lifetime of a connection and released when its loop ends.
+    let max_connections = args.max_connections.max(1);
+    let semaphore = Arc::new(Semaphore::new(max_connections));
+
+    // The WebSocket listener is optional; when no --ws-port is given we
+    // bind nothing and only serve raw TCP.
+    let ws_listener = match args.ws_port {
+        Some(ws_port) => Some(start_ws_server(ws_port).await),
+        None => None,
+    };
+
     loop {
-        match listener.accept().await {
-            Ok((mut stream, _)) => {
-                let storage_clone = storage.clone();
-                tokio::spawn(async move {
-                    process_connection_loop(storage_clone, &mut stream).await;
-                });
+        tokio::select! {
+            accepted = listener.accept() => {
+                match accepted {
+                    Ok((stream, _)) => {
+                        let Some(permit) = acquire_permit(&semaphore).await else { continue };
+                        let storage_clone = storage.clone();
+                        let pubsub_clone = pubsub.clone();
+                        let requirepass = requirepass.clone();
+                        let encryption_key = encryption_key.clone();
+                        tokio::spawn(async move {
+                            let mut transport = TcpTransport::new(stream);
+                            process_connection_loop(
+                                storage_clone,
+                                pubsub_clone,
+                                &mut transport,
+                                requirepass,
+                                encryption_key,
+                            )
+                            .await;
+                            drop(permit);
+                        });
+                    }
+                    Err(e) => warn!("Error listening to socket, {e}"),
+                }
+            }
+            accepted = accept_ws(ws_listener.as_ref()) => {
+                match accepted {
+                    Ok(stream) => {
+                        let Some(permit) = acquire_permit(&semaphore).await else { continue };
+                        let storage_clone = storage.clone();
+                        let pubsub_clone = pubsub.clone();
+                        let requirepass = requirepass.clone();
+                        let encryption_key = encryption_key.clone();
+                        tokio::spawn(async move {
+                            // Complete the WebSocket handshake before the
+                            // connection joins the shared command loop.
+                            let socket = match tokio_tungstenite::accept_async(stream).await {
+                                Ok(socket) => socket,
+                                Err(e) => {
+                                    debug!("WebSocket handshake failed {e}");
+                                    drop(permit);
+                                    return;
+                                }
+                            };
+                            let mut transport = WebSocketTransport::new(socket);
+                            process_connection_loop(
+                                storage_clone,
+                                pubsub_clone,
+                                &mut transport,
+                                requirepass,
+                                encryption_key,
+                            )
+                            .await;
+                            drop(permit);
+                        });
+                    }
+                    Err(e) => warn!("Error listening to WebSocket socket, {e}"),
+                }
             }
-            Err(e) => warn!("Error listening to socket, {e}"),
+            _ = tokio::signal::ctrl_c() => {
+                info!("Shutdown signal received, draining connections");
+                break;
+            }
+        }
+    }
+
+    // Stop accepting and wait for every in-flight connection to release
+    // its permit before exiting, so buffered state is never lost. A
+    // client parked in `read_frame` (idle or subscribed) holds its permit
+    // indefinitely, so the drain is bounded by a deadline after which we
+    // exit regardless rather than hanging forever.
+    match tokio::time::timeout(
+        SHUTDOWN_DRAIN_TIMEOUT,
+        semaphore.acquire_many(max_connections as u32),
+    )
+    .await
+    {
+        Ok(_) => info!("All connections drained"),
+        Err(_) => warn!(
+            "Drain deadline of {SHUTDOWN_DRAIN_TIMEOUT:?} reached with connections still open, exiting anyway"
+        ),
+    }
+
+    if args.enable_scheduled_save {
+        info!("Running final save before shutdown");
+        if let Err(e) = storage.save().await {
+            warn!("Error saving storage during shutdown {e}");
         }
     }
+    info!("Ssache shut down gracefully");
 }
 
 /// Generates an infinte loop with the connection to handle the
 /// requests. The loop is only broken if the request is an empty
 /// stream.
-async fn process_connection_loop(storage: Arc<ShardedStorage>, stream: &mut TcpStream) {
+async fn process_connection_loop<T: Transport>(
+    storage: Arc<ShardedStorage>,
+    pubsub: Arc<PubSub>,
+    transport: &mut T,
+    requirepass: Option<String>,
+    encryption_key: Option<Vec<u8>>,
+) {
+    // Negotiate the optional compression/encryption codecs before any
+    // command is handled. Plaintext clients that skip the handshake
+    // get the none/none default and their first command line back.
+    let (codec, mut pending) = codec::Codec::negotiate(transport, encryption_key).await;
+
+    // A connection starts authenticated when the server has no
+    // required password, otherwise it must issue a valid AUTH first.
+    let mut authenticated = requirepass.is_none();
+
+    // Per-connection pub/sub state: a unique id, a single delivery
+    // queue reused across every subscribed channel, and the set of
+    // channels currently joined.
+    let subscriber_id = pubsub.next_subscriber_id();
+    let (sender, mut receiver) = mpsc::unbounded_channel::<pubsub::Message>();
+    let mut subscriptions: HashSet<String> = HashSet::new();
+
+    // Transaction state for MULTI/EXEC/DISCARD, scoped to this
+    // connection and reset when a block is flushed or discarded.
+    let mut transaction = command::TransactionState::new();
+
     loop {
-        let storage_clone = storage.clone();
-        match handle_request(stream, storage_clone).await {
-            Ok(_) => continue,
-            Err(e) => {
-                match e {
-                    errors::SsacheError::NoDataReceived => break,
-                    _ => warn!("Error executing stream"),
-                };
+        // Read and parse the next batch. Only this step — which is
+        // cancel-safe — races against pub/sub delivery in the `select!`;
+        // the batch is then dispatched to completion below, outside the
+        // `select!`, so a delivery becoming ready mid-command can never
+        // drop an in-flight reply or the rest of a pipelined batch.
+        let read = if subscriptions.is_empty() {
+            read_commands(transport, &codec, &mut pending).await
+        } else {
+            tokio::select! {
+                result = read_commands(transport, &codec, &mut pending) => result,
+                Some(message) = receiver.recv() => {
+                    let response = format!(
+                        "*3{CRLF}$7{CRLF}message{CRLF}${}{CRLF}{}{CRLF}${}{CRLF}{}{CRLF}",
+                        message.channel.len(),
+                        message.channel,
+                        message.payload.len(),
+                        message.payload,
+                    );
+                    send_response(transport, response, &codec).await;
+                    continue;
+                }
             }
+        };
+
+        let commands = match read {
+            Ok(commands) => commands,
+            Err(errors::SsacheError::NoDataReceived) => break,
+            // A malformed command (e.g. an unterminated quote or a
+            // missing parameter) is reported back to the client without
+            // tearing the connection down.
+            Err(errors::SsacheError::SyntaxError { message })
+            | Err(errors::SsacheError::NotEnoughParameters { message }) => {
+                send_response(transport, message, &codec).await;
+                continue;
+            }
+            Err(_) => {
+                warn!("Error executing stream");
+                continue;
+            }
+        };
+
+        match run_commands(
+            commands,
+            transport,
+            &storage,
+            &pubsub,
+            subscriber_id,
+            &sender,
+            &mut subscriptions,
+            &requirepass,
+            &mut authenticated,
+            &codec,
+            &mut transaction,
+        )
+        .await
+        {
+            Ok(_) => continue,
+            Err(errors::SsacheError::NoDataReceived) => break,
+            Err(_) => warn!("Error executing stream"),
         }
     }
+
+    // Drop every subscription when the connection ends so the registry
+    // does not leak closed connections.
+    pubsub.unsubscribe(subscriber_id, &[]).await;
 }
 
 const CRLF: &str = "\r\n";
 
-async fn handle_request(
-    mut stream: &mut TcpStream,
-    storage: Arc<ShardedStorage>,
+/// Reads and parses the next request buffer into its commands. This is
+/// the only cancel-safe step of the request cycle: [`Transport::read_frame`]
+/// may be dropped by a `select!` race without losing data, and the
+/// parsing that follows it is synchronous, so the whole call is safe to
+/// use as a `select!` branch. Dispatching the returned commands must
+/// happen afterwards, outside the `select!`, because command execution
+/// is not cancel-safe.
+async fn read_commands<T: Transport>(
+    transport: &mut T,
+    codec: &codec::Codec,
+    pending: &mut Option<String>,
+) -> Result<Vec<command::Command>, errors::SsacheError> {
+    let line = match pending.take() {
+        // A command line already read during the handshake.
+        Some(line) => line,
+        None => {
+            let raw = transport.read_frame().await?;
+            // Route the inbound bytes back through the negotiated codec
+            // before interpreting them as a command line.
+            String::from_utf8_lossy(&codec.decode(raw)).into_owned()
+        }
+    };
+
+    // A single request buffer may carry several commands back to back;
+    // parse them all so each can be dispatched in order and pipelined
+    // responses stream back in the same order as the requests.
+    match command::parse_pipeline(&line) {
+        // A blank buffer is treated as a closed stream, as before.
+        Ok(commands) if commands.is_empty() => Err(errors::SsacheError::NoDataReceived),
+        Ok(commands) => Ok(commands),
+        Err(e) => Err(e),
+    }
+}
+
+/// Dispatches a parsed batch to completion. Runs outside the pub/sub
+/// `select!` so an in-flight command — or the remainder of a pipelined
+/// batch — is never dropped when a delivery becomes ready; pending
+/// deliveries queue on the receiver and flush on the next loop turn.
+#[allow(clippy::too_many_arguments)]
+async fn run_commands<T: Transport>(
+    commands: Vec<command::Command>,
+    transport: &mut T,
+    storage: &Arc<ShardedStorage>,
+    pubsub: &Arc<PubSub>,
+    subscriber_id: u64,
+    sender: &UnboundedSender<pubsub::Message>,
+    subscriptions: &mut HashSet<String>,
+    requirepass: &Option<String>,
+    authenticated: &mut bool,
+    codec: &codec::Codec,
+    transaction: &mut command::TransactionState,
 ) -> Result<(), errors::SsacheError> {
-    let buf_reader = BufReader::new(&mut stream);
-    let command_line = parse_command_line_from_stream(buf_reader).await?;
+    for command in commands {
+        dispatch_command(
+            command,
+            storage,
+            pubsub,
+            subscriber_id,
+            sender,
+            subscriptions,
+            requirepass,
+            authenticated,
+            transport,
+            codec,
+            transaction,
+        )
+        .await?;
+    }
+    Ok(())
+}
 
-    let command = command::parse_command(command_line);
+/// Dispatches a single parsed command: enforces the authentication gate,
+/// then routes it through the transaction layer, running it immediately
+/// or buffering it inside an open `MULTI` block.
+#[allow(clippy::too_many_arguments)]
+async fn dispatch_command<T: Transport>(
+    command: command::Command,
+    storage: &Arc<ShardedStorage>,
+    pubsub: &Arc<PubSub>,
+    subscriber_id: u64,
+    sender: &UnboundedSender<pubsub::Message>,
+    subscriptions: &mut HashSet<String>,
+    requirepass: &Option<String>,
+    authenticated: &mut bool,
+    transport: &mut T,
+    codec: &codec::Codec,
+    transaction: &mut command::TransactionState,
+) -> Result<(), errors::SsacheError> {
+    // While the connection is unauthenticated only PING, QUIT and the
+    // AUTH handshake itself are allowed, every other command is
+    // rejected with a NOAUTH error.
+    if !*authenticated
+        && !matches!(
+            command,
+            command::Command::Auth { .. }
+                | command::Command::Ping { .. }
+                | command::Command::Quit
+        )
+    {
+        let response = format!("-NOAUTH Authentication required{CRLF}");
+        send_response(transport, response, codec).await;
+        return Ok(());
+    }
 
-    if let Err(e) = command {
-        return match e {
-            errors::SsacheError::NotEnoughParameters { message } => {
-                send_response(stream, message.clone()).await;
-                Err(errors::SsacheError::NotEnoughParameters { message })
+    // Route the command through the transaction layer: inside a MULTI
+    // block it is buffered, otherwise it runs immediately.
+    match command::queue_or_dispatch(transaction, command) {
+        command::Dispatch::Run(command) => {
+            execute_command(
+                command,
+                storage,
+                pubsub,
+                subscriber_id,
+                sender,
+                subscriptions,
+                authenticated,
+                requirepass,
+                transport,
+                codec,
+            )
+            .await
+        }
+        command::Dispatch::Began | command::Dispatch::Discarded => {
+            send_response(transport, format!("+OK{CRLF}"), codec).await;
+            Ok(())
+        }
+        command::Dispatch::Queued => {
+            send_response(transport, format!("+QUEUED{CRLF}"), codec).await;
+            Ok(())
+        }
+        command::Dispatch::Execute(commands) => {
+            // EXEC runs the queued commands in the order they were
+            // queued, their responses streamed back-to-back like a
+            // pipeline. Execution is best-effort ordered, not atomic: no
+            // lock is held across the batch, so commands from other
+            // connections may interleave between the queued ones.
+            for command in commands {
+                execute_command(
+                    command,
+                    storage,
+                    pubsub,
+                    subscriber_id,
+                    sender,
+                    subscriptions,
+                    authenticated,
+                    requirepass,
+                    transport,
+                    codec,
+                )
+                .await?;
             }
-            _ => return Err(e),
-        };
+            Ok(())
+        }
+        command::Dispatch::Error(message) => {
+            send_response(transport, message, codec).await;
+            Ok(())
+        }
     }
+}
 
-    let command = command.unwrap();
+#[allow(clippy::too_many_arguments)]
+async fn execute_command<T: Transport>(
+    command: command::Command,
+    storage: &Arc<ShardedStorage>,
+    pubsub: &Arc<PubSub>,
+    subscriber_id: u64,
+    sender: &UnboundedSender<pubsub::Message>,
+    subscriptions: &mut HashSet<String>,
+    authenticated: &mut bool,
+    requirepass: &Option<String>,
+    transport: &mut T,
+    codec: &codec::Codec,
+) -> Result<(), errors::SsacheError> {
     match command {
         command::Command::Get { key } => {
             let response = match storage.get(key).await {
@@ -207,73 +778,100 @@ async fn handle_request(
                     format!("$-1{CRLF}")
                 }
             };
-            send_response(stream, response).await;
+            send_response(transport, response, codec).await;
             Ok(())
         }
-        command::Command::Set { key, value } => {
+        command::Command::Set {
+            key,
+            value,
+            substitute,
+        } => {
+            // Resolve any `${other_key}` references against the current
+            // store before writing, interpolating a missing key to an
+            // empty string.
+            let value = if substitute {
+                let mut resolved = HashMap::new();
+                for name in command::referenced_keys(&value).unwrap_or_default() {
+                    let current = storage.get(name.clone()).await.unwrap_or_default();
+                    resolved.insert(name, current);
+                }
+                command::expand_value(&value, &resolved)
+            } else {
+                value
+            };
             storage.set(key, value).await;
             let response = format!("+OK{CRLF}");
-            send_response(stream, response).await;
+            send_response(transport, response, codec).await;
             Ok(())
         }
         command::Command::Expire { key, time } => {
             storage.set_expiration(key, time).await;
             let response = format!("+OK{CRLF}");
-            send_response(stream, response).await;
+            send_response(transport, response, codec).await;
             Ok(())
         }
         command::Command::Incr { key } => {
             let response = match storage.incr(key).await {
                 Ok(value) => format!(":{value}{CRLF}"),
-                Err(e) => {
-                    match e.kind() {
-                        std::num::IntErrorKind::Empty => {
-                            format!("-ERROR the value is empty, impossible to convert to a number{CRLF}")
-                        }
-                        std::num::IntErrorKind::InvalidDigit => {
-                            format!("-ERROR the value is not a valid number{CRLF}")
-                        }
-                        std::num::IntErrorKind::NegOverflow => {
-                            format!("-ERROR negative overflow{CRLF}")
-                        }
-                        std::num::IntErrorKind::PosOverflow => {
-                            format!("-ERROR positive overflow{CRLF}")
-                        }
-                        &_ => {
-                            debug!("unkwon error incrementing key {e}");
-                            format!("-ERROR unknown error {CRLF}")
-                        }
-                    }
-                }
+                Err(e) => increment_error_response(e),
             };
-            send_response(stream, response).await;
+            send_response(transport, response, codec).await;
             Ok(())
         }
         command::Command::Decr { key } => {
             let response = match storage.decr(key).await {
                 Ok(value) => format!(":{value}{CRLF}"),
-                Err(e) => {
-                    match e.kind() {
-                        std::num::IntErrorKind::Empty => {
-                            format!("-ERROR the value is empty, impossible to convert to a number{CRLF}")
-                        }
-                        std::num::IntErrorKind::InvalidDigit => {
-                            format!("-ERROR the value is not a valid number{CRLF}")
-                        }
-                        std::num::IntErrorKind::NegOverflow => {
-                            format!("-ERROR negative overflow{CRLF}")
-                        }
-                        std::num::IntErrorKind::PosOverflow => {
-                            format!("-ERROR positive overflow{CRLF}")
-                        }
-                        &_ => {
-                            debug!("unkwon error incrementing key {e}");
-                            format!("-ERROR unknown error {CRLF}")
-                        }
+                Err(e) => increment_error_response(e),
+            };
+            send_response(transport, response, codec).await;
+            Ok(())
+        }
+        command::Command::IncrBy { key, amount } => {
+            let response = match storage.incr_by(key, amount).await {
+                Ok(value) => format!(":{value}{CRLF}"),
+                Err(e) => increment_error_response(e),
+            };
+            send_response(transport, response, codec).await;
+            Ok(())
+        }
+        command::Command::DecrBy { key, amount } => {
+            let response = match storage.decr_by(key, amount).await {
+                Ok(value) => format!(":{value}{CRLF}"),
+                Err(e) => increment_error_response(e),
+            };
+            send_response(transport, response, codec).await;
+            Ok(())
+        }
+        command::Command::Del { keys } => {
+            let mut removed = 0;
+            for key in keys {
+                if storage.remove(key).await {
+                    removed += 1;
+                }
+            }
+            let response = format!(":{removed}{CRLF}");
+            send_response(transport, response, codec).await;
+            Ok(())
+        }
+        command::Command::MGet { keys } => {
+            let mut response = format!("*{}{CRLF}", keys.len());
+            for key in keys {
+                match storage.get(key).await {
+                    Some(value) => {
+                        response.push_str(&format!("${}{CRLF}+{}{CRLF}", value.len(), value))
                     }
+                    None => response.push_str(&format!("$-1{CRLF}")),
                 }
-            };
-            send_response(stream, response).await;
+            }
+            send_response(transport, response, codec).await;
+            Ok(())
+        }
+        command::Command::MSet { pairs } => {
+            for (key, value) in pairs {
+                storage.set(key, value).await;
+            }
+            let response = format!("+OK{CRLF}");
+            send_response(transport, response, codec).await;
             Ok(())
         }
         command::Command::Save => {
@@ -291,7 +889,7 @@ async fn handle_request(
                     }
                 },
             };
-            send_response(stream, response).await;
+            send_response(transport, response, codec).await;
             Ok(())
         }
         command::Command::Load => {
@@ -306,15 +904,13 @@ async fn handle_request(
                     }
                 },
             };
-            send_response(stream, response).await;
+            send_response(transport, response, codec).await;
             Ok(())
         }
         command::Command::Quit => {
             let response = format!("+OK{CRLF}");
-            send_response(stream, response).await;
-            if let Err(e) = stream.shutdown().await {
-                debug!("Error shutting down stream {e}");
-            }
+            send_response(transport, response, codec).await;
+            transport.shutdown().await;
             Ok(())
         }
         command::Command::Ping { message } => {
@@ -324,41 +920,265 @@ async fn handle_request(
             } else {
                 format!("${size}{CRLF}+{message}{CRLF}")
             };
-            send_response(stream, response).await;
+            send_response(transport, response, codec).await;
+            Ok(())
+        }
+        command::Command::Auth { password } => {
+            let response = match requirepass {
+                Some(secret) if !password.is_empty() && &password == secret => {
+                    *authenticated = true;
+                    format!("+OK{CRLF}")
+                }
+                _ => format!("-ERR invalid password{CRLF}"),
+            };
+            send_response(transport, response, codec).await;
+            Ok(())
+        }
+        command::Command::Subscribe { channels } => {
+            pubsub.subscribe(subscriber_id, &channels, sender).await;
+            // Acknowledge each channel with the running subscription
+            // count, mirroring the Redis subscribe reply.
+            let mut response = String::new();
+            for channel in &channels {
+                subscriptions.insert(channel.clone());
+                response.push_str(&format!(
+                    "*3{CRLF}$9{CRLF}subscribe{CRLF}${}{CRLF}{}{CRLF}:{}{CRLF}",
+                    channel.len(),
+                    channel,
+                    subscriptions.len()
+                ));
+            }
+            send_response(transport, response, codec).await;
+            Ok(())
+        }
+        command::Command::Unsubscribe { channels } => {
+            pubsub.unsubscribe(subscriber_id, &channels).await;
+            // An empty channel list unsubscribes from everything.
+            let removed: Vec<String> = if channels.is_empty() {
+                subscriptions.drain().collect()
+            } else {
+                for channel in &channels {
+                    subscriptions.remove(channel);
+                }
+                channels
+            };
+            let mut response = String::new();
+            for channel in &removed {
+                response.push_str(&format!(
+                    "*3{CRLF}$11{CRLF}unsubscribe{CRLF}${}{CRLF}{}{CRLF}:{}{CRLF}",
+                    channel.len(),
+                    channel,
+                    subscriptions.len()
+                ));
+            }
+            send_response(transport, response, codec).await;
+            Ok(())
+        }
+        command::Command::Publish { channel, message } => {
+            let receivers = pubsub.publish(&channel, &message).await;
+            let response = format!(":{receivers}{CRLF}");
+            send_response(transport, response, codec).await;
+            Ok(())
+        }
+        command::Command::Multi | command::Command::Exec | command::Command::Discard => {
+            // Transaction control commands are intercepted by
+            // queue_or_dispatch and never reach the executor.
             Ok(())
         }
         command::Command::Unknown => {
             debug!("Unknown command");
             let response = format!("-ERROR unknown command{CRLF}");
-            send_response(stream, response).await;
+            send_response(transport, response, codec).await;
             Ok(())
         }
     }
 }
 
-async fn send_response(stream: &mut TcpStream, response: String) {
-    match stream.write_all(response.as_bytes()).await {
-        Ok(_) => trace!("Response sent to client"),
-        Err(e) => debug!("Unable to send response to client {e}"),
+async fn send_response<T: Transport>(transport: &mut T, response: String, codec: &codec::Codec) {
+    let bytes = codec.encode(response.into_bytes());
+    transport.write_frame(&bytes).await;
+}
+
+/// Maps a failed integer parse from an INCR/DECR family command onto the
+/// matching protocol error line.
+fn increment_error_response(e: std::num::ParseIntError) -> String {
+    match e.kind() {
+        std::num::IntErrorKind::Empty => {
+            format!("-ERROR the value is empty, impossible to convert to a number{CRLF}")
+        }
+        std::num::IntErrorKind::InvalidDigit => {
+            format!("-ERROR the value is not a valid number{CRLF}")
+        }
+        std::num::IntErrorKind::NegOverflow => {
+            format!("-ERROR negative overflow{CRLF}")
+        }
+        std::num::IntErrorKind::PosOverflow => {
+            format!("-ERROR positive overflow{CRLF}")
+        }
+        &_ => {
+            debug!("unkwon error incrementing key {e}");
+            format!("-ERROR unknown error {CRLF}")
+        }
     }
 }
 
-async fn parse_command_line_from_stream(
-    mut buf_reader: BufReader<&mut &mut TcpStream>,
-) -> Result<Vec<String>, errors::SsacheError> {
-    let mut command_line = String::new();
-    let result = buf_reader.read_line(&mut command_line).await;
-    if result.is_err() {
-        return Err(errors::SsacheError::NoDataReceived);
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+
+    use super::*;
+
+    /// An in-memory [`Transport`] that hands back no input and records
+    /// every response written to it, so the authentication gate can be
+    /// exercised without a real socket.
+    struct MockTransport {
+        outbound: Vec<Vec<u8>>,
     }
-    let command_line = command_line.split_whitespace();
-    let command_line: Vec<String> = command_line
-        .into_iter()
-        .map(|slice| slice.to_string())
-        .collect();
-    if command_line.get(0).is_none() {
-        return Err(errors::SsacheError::NoDataReceived);
+
+    impl MockTransport {
+        fn new() -> MockTransport {
+            MockTransport {
+                outbound: Vec::new(),
+            }
+        }
+
+        fn last_response(&self) -> String {
+            String::from_utf8_lossy(self.outbound.last().expect("no response was sent")).into_owned()
+        }
     }
 
-    Ok(command_line)
+    #[async_trait]
+    impl Transport for MockTransport {
+        async fn read_frame(&mut self) -> Result<Vec<u8>, errors::SsacheError> {
+            Err(errors::SsacheError::NoDataReceived)
+        }
+
+        async fn write_frame(&mut self, bytes: &[u8]) {
+            self.outbound.push(bytes.to_vec());
+        }
+
+        async fn shutdown(&mut self) {}
+
+        fn preserves_message_boundaries(&self) -> bool {
+            false
+        }
+    }
+
+    /// Drives a single `dispatch_command` call against a fresh, unused
+    /// shard of storage and pub/sub, returning the last response line so
+    /// each test can assert on the NOAUTH gate in isolation.
+    async fn dispatch(command: command::Command, requirepass: &Option<String>, authenticated: &mut bool) -> String {
+        let storage = Arc::new(ShardedStorage::new(1, 16, 4096, 4, Vec::new()));
+        let pubsub = Arc::new(PubSub::new());
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        let mut subscriptions = HashSet::new();
+        let mut transaction = command::TransactionState::new();
+        let mut transport = MockTransport::new();
+        let codec = codec::Codec::negotiate(&mut MockTransport::new(), None).await.0;
+
+        dispatch_command(
+            command,
+            &storage,
+            &pubsub,
+            0,
+            &sender,
+            &mut subscriptions,
+            requirepass,
+            authenticated,
+            &mut transport,
+            &codec,
+            &mut transaction,
+        )
+        .await
+        .unwrap();
+
+        transport.last_response()
+    }
+
+    #[tokio::test]
+    async fn unauthenticated_command_is_rejected_with_noauth() {
+        let requirepass = Some("secret".to_string());
+        let mut authenticated = false;
+
+        let response = dispatch(
+            command::Command::Get { key: "x".to_string() },
+            &requirepass,
+            &mut authenticated,
+        )
+        .await;
+
+        assert!(response.starts_with("-NOAUTH"));
+        assert!(!authenticated);
+    }
+
+    #[tokio::test]
+    async fn unauthenticated_ping_auth_and_quit_bypass_the_gate() {
+        let requirepass = Some("secret".to_string());
+
+        let mut authenticated = false;
+        let ping = dispatch(
+            command::Command::Ping { message: String::new() },
+            &requirepass,
+            &mut authenticated,
+        )
+        .await;
+        assert!(!ping.starts_with("-NOAUTH"));
+
+        let mut authenticated = false;
+        let quit = dispatch(command::Command::Quit, &requirepass, &mut authenticated).await;
+        assert!(!quit.starts_with("-NOAUTH"));
+
+        let mut authenticated = false;
+        let wrong_password = dispatch(
+            command::Command::Auth {
+                password: "nope".to_string(),
+            },
+            &requirepass,
+            &mut authenticated,
+        )
+        .await;
+        assert!(!wrong_password.starts_with("-NOAUTH"));
+        assert!(!authenticated);
+    }
+
+    #[tokio::test]
+    async fn correct_auth_password_authenticates_and_unlocks_commands() {
+        let requirepass = Some("secret".to_string());
+        let mut authenticated = false;
+
+        let auth_response = dispatch(
+            command::Command::Auth {
+                password: "secret".to_string(),
+            },
+            &requirepass,
+            &mut authenticated,
+        )
+        .await;
+        assert_eq!(auth_response, format!("+OK{CRLF}"));
+        assert!(authenticated);
+
+        let get_response = dispatch(
+            command::Command::Get { key: "x".to_string() },
+            &requirepass,
+            &mut authenticated,
+        )
+        .await;
+        assert!(!get_response.starts_with("-NOAUTH"));
+    }
+
+    #[tokio::test]
+    async fn no_requirepass_means_every_command_is_already_authenticated() {
+        let requirepass = None;
+        let mut authenticated = true;
+
+        let response = dispatch(
+            command::Command::Get { key: "x".to_string() },
+            &requirepass,
+            &mut authenticated,
+        )
+        .await;
+
+        assert!(!response.starts_with("-NOAUTH"));
+    }
 }
+
@@ -0,0 +1,204 @@
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use tokio::sync::{mpsc::UnboundedSender, Mutex};
+use tracing::trace;
+
+/// A message delivered to a subscriber, carrying the originating
+/// channel and the published payload.
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub channel: String,
+    pub payload: String,
+}
+
+struct Subscriber {
+    id: u64,
+    sender: UnboundedSender<Message>,
+}
+
+/// A registry of channels to the connections subscribed to them,
+/// shared across all connections through an `Arc`. It turns ssache
+/// into a lightweight message bus alongside the key-value store.
+pub struct PubSub {
+    channels: Mutex<HashMap<String, Vec<Subscriber>>>,
+    next_id: AtomicU64,
+}
+
+impl PubSub {
+    pub fn new() -> PubSub {
+        PubSub {
+            channels: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Allocates a unique identifier for a connection so its
+    /// subscriptions can be removed independently of other connections
+    /// sharing the same channel.
+    pub fn next_subscriber_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Registers the subscriber on each channel, reusing the
+    /// connection's single delivery sender so the connection only has
+    /// to await one receiver regardless of how many channels it joins.
+    pub async fn subscribe(&self, id: u64, channels: &[String], sender: &UnboundedSender<Message>) {
+        let mut registry = self.channels.lock().await;
+        for channel in channels {
+            let subscribers = registry.entry(channel.clone()).or_default();
+            if !subscribers.iter().any(|s| s.id == id) {
+                subscribers.push(Subscriber {
+                    id,
+                    sender: sender.clone(),
+                });
+            }
+        }
+    }
+
+    /// Removes the subscriber from the given channels, or from every
+    /// channel when `channels` is empty.
+    pub async fn unsubscribe(&self, id: u64, channels: &[String]) {
+        let mut registry = self.channels.lock().await;
+        if channels.is_empty() {
+            for subscribers in registry.values_mut() {
+                subscribers.retain(|s| s.id != id);
+            }
+        } else {
+            for channel in channels {
+                if let Some(subscribers) = registry.get_mut(channel) {
+                    subscribers.retain(|s| s.id != id);
+                }
+            }
+        }
+        registry.retain(|_, subscribers| !subscribers.is_empty());
+    }
+
+    /// Publishes a message to every subscriber of the channel, returning
+    /// the number of connections that received it. Subscribers whose
+    /// queue has been dropped are pruned lazily.
+    pub async fn publish(&self, channel: &str, payload: &str) -> usize {
+        let mut registry = self.channels.lock().await;
+        let subscribers = match registry.get_mut(channel) {
+            Some(subscribers) => subscribers,
+            None => return 0,
+        };
+        let message = Message {
+            channel: channel.to_string(),
+            payload: payload.to_string(),
+        };
+        let mut receivers = 0;
+        subscribers.retain(|subscriber| match subscriber.sender.send(message.clone()) {
+            Ok(()) => {
+                receivers += 1;
+                true
+            }
+            Err(_) => {
+                trace!("Dropping closed subscriber {}", subscriber.id);
+                false
+            }
+        });
+        receivers
+    }
+}
+
+impl Default for PubSub {
+    fn default() -> Self {
+        PubSub::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    #[tokio::test]
+    async fn publish_delivers_to_every_subscriber_of_the_channel() {
+        let pubsub = PubSub::new();
+        let (tx1, mut rx1) = unbounded_channel();
+        let (tx2, mut rx2) = unbounded_channel();
+        let id1 = pubsub.next_subscriber_id();
+        let id2 = pubsub.next_subscriber_id();
+        pubsub.subscribe(id1, &["news".to_string()], &tx1).await;
+        pubsub.subscribe(id2, &["news".to_string()], &tx2).await;
+
+        let receivers = pubsub.publish("news", "hello").await;
+
+        assert_eq!(receivers, 2);
+        assert_eq!(rx1.recv().await.unwrap().payload, "hello");
+        assert_eq!(rx2.recv().await.unwrap().payload, "hello");
+    }
+
+    #[tokio::test]
+    async fn publish_to_unknown_channel_reaches_nobody() {
+        let pubsub = PubSub::new();
+
+        let receivers = pubsub.publish("nobody-home", "hello").await;
+
+        assert_eq!(receivers, 0);
+    }
+
+    #[tokio::test]
+    async fn unsubscribe_from_one_channel_keeps_others() {
+        let pubsub = PubSub::new();
+        let (tx, mut rx) = unbounded_channel();
+        let id = pubsub.next_subscriber_id();
+        pubsub
+            .subscribe(id, &["a".to_string(), "b".to_string()], &tx)
+            .await;
+
+        pubsub.unsubscribe(id, &["a".to_string()]).await;
+
+        assert_eq!(pubsub.publish("a", "x").await, 0);
+        assert_eq!(pubsub.publish("b", "y").await, 1);
+        assert_eq!(rx.recv().await.unwrap().payload, "y");
+    }
+
+    #[tokio::test]
+    async fn unsubscribe_with_no_channels_removes_from_all() {
+        let pubsub = PubSub::new();
+        let (tx, _rx) = unbounded_channel();
+        let id = pubsub.next_subscriber_id();
+        pubsub
+            .subscribe(id, &["a".to_string(), "b".to_string()], &tx)
+            .await;
+
+        pubsub.unsubscribe(id, &[]).await;
+
+        assert_eq!(pubsub.publish("a", "x").await, 0);
+        assert_eq!(pubsub.publish("b", "y").await, 0);
+    }
+
+    #[tokio::test]
+    async fn subscribing_twice_does_not_duplicate_delivery() {
+        let pubsub = PubSub::new();
+        let (tx, mut rx) = unbounded_channel();
+        let id = pubsub.next_subscriber_id();
+        pubsub.subscribe(id, &["news".to_string()], &tx).await;
+        pubsub.subscribe(id, &["news".to_string()], &tx).await;
+
+        assert_eq!(pubsub.publish("news", "hello").await, 1);
+        assert_eq!(rx.recv().await.unwrap().payload, "hello");
+    }
+
+    #[tokio::test]
+    async fn publish_prunes_subscribers_whose_receiver_dropped() {
+        let pubsub = PubSub::new();
+        let (tx, rx) = unbounded_channel();
+        let id = pubsub.next_subscriber_id();
+        pubsub.subscribe(id, &["news".to_string()], &tx).await;
+        drop(rx);
+
+        assert_eq!(pubsub.publish("news", "hello").await, 0);
+        // The dead subscriber was pruned, so a fresh subscription is the
+        // only one left on the channel.
+        let (tx2, mut rx2) = unbounded_channel();
+        let id2 = pubsub.next_subscriber_id();
+        pubsub.subscribe(id2, &["news".to_string()], &tx2).await;
+        assert_eq!(pubsub.publish("news", "again").await, 1);
+        assert_eq!(rx2.recv().await.unwrap().payload, "again");
+    }
+}
@@ -0,0 +1,300 @@
+use async_trait::async_trait;
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use tracing::{debug, trace};
+
+use crate::errors::SsacheError;
+
+/// The transport-specific read/write surface shared by the raw TCP and
+/// WebSocket listeners. The command-dispatch core only ever sees raw
+/// frames, so the same `process_connection_loop` path serves both.
+#[async_trait]
+pub trait Transport: Send {
+    /// Reads the next raw command frame, yielding [`SsacheError::NoDataReceived`]
+    /// when the peer has gone away.
+    async fn read_frame(&mut self) -> Result<Vec<u8>, SsacheError>;
+
+    /// Writes a raw response frame to the peer.
+    async fn write_frame(&mut self, bytes: &[u8]);
+
+    /// Closes the connection.
+    async fn shutdown(&mut self);
+
+    /// Whether this transport preserves message boundaries for arbitrary
+    /// binary payloads without extra framing. WebSocket does; the raw TCP
+    /// transport is line-delimited and relies on [`Transport::enable_framed_mode`]
+    /// instead when a binary codec is negotiated.
+    fn preserves_message_boundaries(&self) -> bool;
+
+    /// Switches the transport to length-prefixed binary framing for the
+    /// rest of its lifetime. Called once a non-`none` codec is negotiated
+    /// so compressed/encrypted frames keep their boundaries over a
+    /// line-delimited transport. Message-framed transports (WebSocket)
+    /// already preserve boundaries and leave this as a no-op.
+    fn enable_framed_mode(&mut self) {}
+}
+
+/// Transport over a raw TCP socket. Starts line-delimited and switches
+/// to length-prefixed binary framing once a binary codec is negotiated.
+pub struct TcpTransport {
+    reader: BufReader<TcpStream>,
+    /// Bytes of a partially read frame retained across calls. Keeping
+    /// them in the transport rather than a local makes [`read_frame`]
+    /// cancel-safe: if the future is dropped mid-frame (e.g. it loses a
+    /// `select!` race to a delivered pub/sub message), the bytes already
+    /// copied out of the `BufReader` survive and the next call resumes
+    /// instead of truncating the next command.
+    pending: Vec<u8>,
+    /// Once a binary codec is negotiated the connection speaks
+    /// length-prefixed frames (a 4-byte big-endian length followed by
+    /// that many payload bytes) instead of `\n`-delimited lines, so the
+    /// binary payload can embed any byte without corrupting framing.
+    framed: bool,
+}
+
+/// Width of the big-endian length prefix on a framed TCP message.
+const FRAME_PREFIX_LEN: usize = 4;
+
+impl TcpTransport {
+    pub fn new(stream: TcpStream) -> TcpTransport {
+        TcpTransport {
+            reader: BufReader::new(stream),
+            pending: Vec::new(),
+            framed: false,
+        }
+    }
+
+    /// Reads one length-prefixed frame, resuming from `self.pending` so
+    /// the read stays cancel-safe: `read_buf` appends straight into the
+    /// retained buffer, so a dropped future leaves the partial frame in
+    /// place for the next call.
+    async fn read_framed_frame(&mut self) -> Result<Vec<u8>, SsacheError> {
+        loop {
+            if self.pending.len() >= FRAME_PREFIX_LEN {
+                let len = u32::from_be_bytes([
+                    self.pending[0],
+                    self.pending[1],
+                    self.pending[2],
+                    self.pending[3],
+                ]) as usize;
+                if self.pending.len() >= FRAME_PREFIX_LEN + len {
+                    let frame = self.pending[FRAME_PREFIX_LEN..FRAME_PREFIX_LEN + len].to_vec();
+                    // Keep any bytes of a following frame for the next read.
+                    self.pending.drain(..FRAME_PREFIX_LEN + len);
+                    return Ok(frame);
+                }
+            }
+            match self.reader.read_buf(&mut self.pending).await {
+                // EOF with an incomplete frame means the peer went away
+                // mid-message; treat it as a closed connection.
+                Ok(0) => return Err(SsacheError::NoDataReceived),
+                Ok(_) => continue,
+                Err(_) => return Err(SsacheError::NoDataReceived),
+            }
+        }
+    }
+
+    async fn write_framed_frame(&mut self, bytes: &[u8]) {
+        let mut frame = Vec::with_capacity(FRAME_PREFIX_LEN + bytes.len());
+        frame.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+        frame.extend_from_slice(bytes);
+        match self.reader.get_mut().write_all(&frame).await {
+            Ok(_) => trace!("Response sent to client"),
+            Err(e) => debug!("Unable to send response to client {e}"),
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for TcpTransport {
+    async fn read_frame(&mut self) -> Result<Vec<u8>, SsacheError> {
+        if self.framed {
+            return self.read_framed_frame().await;
+        }
+        // `read_until` is cancel-safe only when the same buffer is reused
+        // across calls: on cancellation the bytes read so far stay in
+        // `self.pending`, so a resumed read continues the same line.
+        match self.reader.read_until(b'\n', &mut self.pending).await {
+            Ok(0) if self.pending.is_empty() => Err(SsacheError::NoDataReceived),
+            Ok(_) => {
+                // Opportunistically drain any further complete lines that
+                // are already sitting in the `BufReader` so a pipelined
+                // batch delivered in one packet is handed to
+                // `parse_pipeline` in a single wakeup instead of costing
+                // one read per command. Only whole lines up to the last
+                // `\n` are taken; a trailing partial line stays buffered
+                // and is resumed by the next read, keeping framing intact.
+                let extra: Vec<u8> = {
+                    let buffered = self.reader.buffer();
+                    match buffered.iter().rposition(|b| *b == b'\n') {
+                        Some(idx) => buffered[..=idx].to_vec(),
+                        None => Vec::new(),
+                    }
+                };
+                if !extra.is_empty() {
+                    self.reader.consume(extra.len());
+                    self.pending.extend_from_slice(&extra);
+                }
+                Ok(std::mem::take(&mut self.pending))
+            }
+            Err(_) => Err(SsacheError::NoDataReceived),
+        }
+    }
+
+    async fn write_frame(&mut self, bytes: &[u8]) {
+        if self.framed {
+            return self.write_framed_frame(bytes).await;
+        }
+        match self.reader.get_mut().write_all(bytes).await {
+            Ok(_) => trace!("Response sent to client"),
+            Err(e) => debug!("Unable to send response to client {e}"),
+        }
+    }
+
+    async fn shutdown(&mut self) {
+        if let Err(e) = self.reader.get_mut().shutdown().await {
+            debug!("Error shutting down stream {e}");
+        }
+    }
+
+    fn enable_framed_mode(&mut self) {
+        self.framed = true;
+    }
+
+    fn preserves_message_boundaries(&self) -> bool {
+        // Until a binary codec switches the connection to length-prefixed
+        // framing, frames are `\n`-delimited and binary codec output
+        // would corrupt the boundaries. Once framed, each frame carries
+        // its own length prefix, so boundaries survive just like on the
+        // WebSocket transport.
+        self.framed
+    }
+}
+
+/// Transport over a WebSocket connection, feeding each text/binary
+/// frame through the same command path and framing responses back as
+/// WebSocket binary messages.
+pub struct WebSocketTransport {
+    socket: WebSocketStream<TcpStream>,
+}
+
+impl WebSocketTransport {
+    pub fn new(socket: WebSocketStream<TcpStream>) -> WebSocketTransport {
+        WebSocketTransport { socket }
+    }
+}
+
+#[async_trait]
+impl Transport for WebSocketTransport {
+    async fn read_frame(&mut self) -> Result<Vec<u8>, SsacheError> {
+        loop {
+            match self.socket.next().await {
+                Some(Ok(Message::Text(text))) => return Ok(text.into_bytes()),
+                Some(Ok(Message::Binary(bytes))) => return Ok(bytes),
+                // Control frames carry no command payload, keep reading.
+                Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                Some(Ok(Message::Close(_))) | None => {
+                    return Err(SsacheError::NoDataReceived)
+                }
+                Some(Ok(Message::Frame(_))) => continue,
+                Some(Err(e)) => {
+                    debug!("WebSocket read error {e}");
+                    return Err(SsacheError::NoDataReceived);
+                }
+            }
+        }
+    }
+
+    async fn write_frame(&mut self, bytes: &[u8]) {
+        match self.socket.send(Message::Binary(bytes.to_vec())).await {
+            Ok(_) => trace!("Response sent to client"),
+            Err(e) => debug!("Unable to send response to client {e}"),
+        }
+    }
+
+    async fn shutdown(&mut self) {
+        if let Err(e) = self.socket.close(None).await {
+            debug!("Error closing WebSocket {e}");
+        }
+    }
+
+    fn preserves_message_boundaries(&self) -> bool {
+        // Each WebSocket message is a self-describing frame, so binary
+        // codec output survives intact.
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// A connected loopback pair: `(client, server)`, where `server` is
+    /// wrapped in the [`TcpTransport`] under test and `client` is the
+    /// peer driving bytes at it.
+    async fn loopback_pair() -> (TcpStream, TcpTransport) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = TcpStream::connect(addr).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, TcpTransport::new(server))
+    }
+
+    #[tokio::test]
+    async fn read_frame_returns_a_line_delimited_command() {
+        let (mut client, mut server) = loopback_pair().await;
+
+        client.write_all(b"PING\r\n").await.unwrap();
+
+        let frame = server.read_frame().await.unwrap();
+        assert_eq!(frame, b"PING\r\n");
+    }
+
+    #[tokio::test]
+    async fn read_frame_drains_pipelined_lines_already_buffered() {
+        let (mut client, mut server) = loopback_pair().await;
+
+        // Both lines land in one write, so the reader should hand back
+        // both in a single `read_frame` call instead of costing a second
+        // wakeup for the already-buffered second line.
+        client.write_all(b"PING\r\nPING\r\n").await.unwrap();
+
+        let frame = server.read_frame().await.unwrap();
+        assert_eq!(frame, b"PING\r\nPING\r\n");
+    }
+
+    #[tokio::test]
+    async fn framed_mode_round_trips_a_length_prefixed_frame() {
+        let (mut client, mut server) = loopback_pair().await;
+        server.enable_framed_mode();
+
+        let payload = vec![0u8, 1, 2, 255, 254];
+        let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(&payload);
+        client.write_all(&frame).await.unwrap();
+
+        let received = server.read_frame().await.unwrap();
+        assert_eq!(received, payload);
+    }
+
+    #[tokio::test]
+    async fn read_frame_on_closed_connection_is_no_data_received() {
+        let (client, mut server) = loopback_pair().await;
+        drop(client);
+
+        let result = server.read_frame().await;
+        assert!(matches!(result, Err(SsacheError::NoDataReceived)));
+    }
+
+    #[tokio::test]
+    async fn tcp_transport_only_preserves_boundaries_once_framed() {
+        let (_client, mut server) = loopback_pair().await;
+
+        assert!(!server.preserves_message_boundaries());
+        server.enable_framed_mode();
+        assert!(server.preserves_message_boundaries());
+    }
+}